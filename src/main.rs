@@ -5,12 +5,13 @@ extern crate pretty_env_logger;
 use glob::glob;
 use lazy_static::lazy_static;
 use log::{debug, error, info, warn};
+use petgraph::algo::tarjan_scc;
 use petgraph::graphmap::DiGraphMap;
-use petgraph::algo::is_cyclic_directed;
 use pulldown_cmark::{CodeBlockKind::Fenced, CowStr::Borrowed, Event, Parser, Tag::CodeBlock};
 use regex::{Regex, RegexBuilder};
-use std::collections::HashMap;
-use std::path::Path;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::{env, fs};
 
 #[derive(Debug)]
@@ -20,7 +21,49 @@ enum CodeMacroParseError {
 
 #[derive(Debug)]
 enum CodeMacroLinkError {
-    CyclicInclusion,
+    CyclicInclusion(Vec<(String, Span, PathBuf)>),
+    UndefinedMacro {
+        name: String,
+        span: Span,
+        origin_file: PathBuf,
+    },
+    ExpansionLimitExceeded {
+        name: String,
+        span: Span,
+        origin_file: PathBuf,
+    },
+}
+
+/// Safety valves in case `link`'s acyclic check is ever wrong or bypassed.
+const EXPANSION_DEPTH_LIMIT: usize = 128;
+const MAX_TOTAL_EXPANSIONS: usize = 4096;
+
+/// A line/column location in a `.md` file, for pointing diagnostics at it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Span {
+    line: usize,
+    column: usize,
+}
+
+fn offset_to_span(source: &str, offset: usize) -> Span {
+    let mut line = 1;
+    let mut line_start = 0;
+    for (i, c) in source[..offset].char_indices() {
+        if c == '\n' {
+            line += 1;
+            line_start = i + 1;
+        }
+    }
+    Span {
+        line,
+        column: offset - line_start + 1,
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct MacroReference {
+    name: String,
+    span: Span,
 }
 
 #[derive(Debug, PartialEq, Eq, Hash)]
@@ -28,34 +71,95 @@ struct CodeMacro {
     name: String,
     content: String,
     uuid: usize,
+    span: Span,
+    references: Vec<MacroReference>,
+    origin_file: PathBuf,
 }
 
-impl TryFrom<String> for CodeMacro {
-    type Error = CodeMacroParseError;
-    fn try_from(text: String) -> Result<Self, Self::Error> {
+impl CodeMacro {
+    /// Parses a fenced code block's text into a `CodeMacro`. `source` is the
+    /// full markdown file contents and `block_start` is the byte offset of
+    /// `text` within it, used to turn match positions into `Span`s.
+    fn parse(
+        text: &str,
+        source: &str,
+        block_start: usize,
+        origin_file: &Path,
+    ) -> Result<Self, CodeMacroParseError> {
         lazy_static! {
             static ref MACRO_IDENT_RE: Regex = Regex::new(r"^//\s*(<<(.+)>>=)\s*\n(.*)").unwrap();
+            static ref MACRO_REF_RE: Regex = RegexBuilder::new(r"^( *)//\s*<<(.+)>>\n")
+                .multi_line(true)
+                .build()
+                .unwrap();
         }
         let captures = MACRO_IDENT_RE
-            .captures(&text)
+            .captures(text)
             .ok_or(CodeMacroParseError::MissingIndentifier)?;
 
-        let definition = captures
+        let definition_match = captures
             .get(1)
-            .ok_or(CodeMacroParseError::MissingIndentifier)?
-            .as_str();
+            .ok_or(CodeMacroParseError::MissingIndentifier)?;
 
         let name = captures
             .get(2)
             .ok_or(CodeMacroParseError::MissingIndentifier)?
             .as_str();
 
+        let span = offset_to_span(source, block_start + definition_match.start());
+
+        let references = MACRO_REF_RE
+            .captures_iter(text)
+            .map(|reference| {
+                let whole_match = reference.get(0).unwrap();
+                MacroReference {
+                    name: reference.get(2).unwrap().as_str().to_owned(),
+                    span: offset_to_span(source, block_start + whole_match.start()),
+                }
+            })
+            .collect();
+
         Ok(CodeMacro {
             name: name.to_owned(),
-            content: text.replace(definition, name),
-            uuid: 0
+            content: text.replace(definition_match.as_str(), name),
+            uuid: 0,
+            span,
+            references,
+            origin_file: origin_file.to_owned(),
         })
     }
+
+    /// Bare `*` roots an output file sibling to the defining `.md` file;
+    /// `*:path/to/out.rs` names an explicit project-relative output path.
+    /// Returns `None` for a non-root macro.
+    fn root_output_path(&self, project_dir: &str) -> Option<PathBuf> {
+        if self.name == "*" {
+            let output_path_name = format!(
+                "{}/{}.rs",
+                self.origin_file.parent()?.to_str()?,
+                self.origin_file.file_stem()?.to_str()?
+            );
+            Some(PathBuf::from(output_path_name))
+        } else {
+            self.name
+                .strip_prefix("*:")
+                .map(|relative_path| Path::new(project_dir).join(relative_path))
+        }
+    }
+
+    /// This macro's key in a project-wide `CodeMacroCollection`. A bare `*`
+    /// root is namespaced by its origin file, so every file may have its own
+    /// `*`; named macros and explicit `*:path` roots still collide globally.
+    fn key(&self) -> String {
+        if self.name == "*" {
+            // The NUL byte can't appear in a macro name parsed out of
+            // markdown text, so this can never collide with a real name
+            // (including another file's literal `*:<path>` root).
+            format!("*\0{}", self.origin_file.display())
+        } else {
+            self.name.clone()
+        }
+    }
 }
 
 type CodeMacroCollection = HashMap<String, CodeMacro>;
@@ -66,44 +170,276 @@ fn prepend_indents(text: &str, indents: usize) -> String {
         .collect()
 }
 
-fn expand_code_macros(code_macros: &CodeMacroCollection) -> String {
-    let mut output = code_macros
-        .get("*")
-        .expect("No root macro found")
-        .content
-        .clone();
+/// Where one line of generated output came from, for `cargo tangle resolve`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct SourceMapEntry {
+    file: String,
+    line: usize,
+}
+
+/// An expanded macro body with one `SourceMapEntry` per line of `text`.
+#[derive(Debug, Clone)]
+struct ExpandedMacro {
+    text: String,
+    line_origins: Vec<SourceMapEntry>,
+}
+
+/// Appends a run of whole lines to `expanded`, recording each line's origin.
+/// Returns the next line number, for the caller to resume counting from.
+fn push_literal_chunk(
+    expanded: &mut ExpandedMacro,
+    chunk: &str,
+    origin_file: &Path,
+    first_line: usize,
+) -> usize {
+    expanded.text.push_str(chunk);
+    let mut line = first_line;
+    for _ in chunk.split_inclusive('\n') {
+        expanded.line_origins.push(SourceMapEntry {
+            file: origin_file.display().to_string(),
+            line,
+        });
+        line += 1;
+    }
+    line
+}
+
+/// Resolves `macro_def`'s body, splicing in the (memoized) expansion of
+/// every macro it references. `depth` is the reference chain length above
+/// this call.
+fn resolve_macro<'a>(
+    code_macros: &'a CodeMacroCollection,
+    macro_def: &'a CodeMacro,
+    macro_re: &Regex,
+    resolved: &mut HashMap<String, ExpandedMacro>,
+    total_expansions: &mut usize,
+    depth: usize,
+) -> Result<ExpandedMacro, CodeMacroLinkError> {
+    if let Some(already_resolved) = resolved.get(&macro_def.name) {
+        return Ok(already_resolved.clone());
+    }
+
+    *total_expansions += 1;
+    if depth > EXPANSION_DEPTH_LIMIT || *total_expansions > MAX_TOTAL_EXPANSIONS {
+        return Err(CodeMacroLinkError::ExpansionLimitExceeded {
+            name: macro_def.name.clone(),
+            span: macro_def.span,
+            origin_file: macro_def.origin_file.clone(),
+        });
+    }
+
+    debug!("Expanding macro {}", macro_def.name);
 
+    let mut expanded = ExpandedMacro {
+        text: String::new(),
+        line_origins: Vec::new(),
+    };
+    let mut last_end = 0;
+    let mut next_markdown_line = macro_def.span.line;
+    for captures in macro_re.captures_iter(&macro_def.content) {
+        let whole_match = captures.get(0).unwrap();
+        next_markdown_line = push_literal_chunk(
+            &mut expanded,
+            &macro_def.content[last_end..whole_match.start()],
+            &macro_def.origin_file,
+            next_markdown_line,
+        );
+
+        let indents = captures.get(1).unwrap().as_str().len() / 4;
+        let referenced_name = captures.get(2).unwrap().as_str();
+        let referenced_macro = code_macros
+            .get(referenced_name)
+            .expect("link() already confirmed every reference resolves before expansion begins");
+        let referenced_expansion = resolve_macro(
+            code_macros,
+            referenced_macro,
+            macro_re,
+            resolved,
+            total_expansions,
+            depth + 1,
+        )?;
+        expanded
+            .text
+            .push_str(&prepend_indents(&referenced_expansion.text, indents));
+        expanded
+            .line_origins
+            .extend(referenced_expansion.line_origins);
+
+        // The reference itself occupied exactly one line of `macro_def`'s
+        // own source, which we don't emit literally but must still count.
+        next_markdown_line += 1;
+        last_end = whole_match.end();
+    }
+    push_literal_chunk(
+        &mut expanded,
+        &macro_def.content[last_end..],
+        &macro_def.origin_file,
+        next_markdown_line,
+    );
+
+    resolved.insert(macro_def.name.clone(), expanded.clone());
+    Ok(expanded)
+}
+
+fn expand_code_macros(
+    code_macros: &CodeMacroCollection,
+    root_name: &str,
+) -> Result<ExpandedMacro, CodeMacroLinkError> {
+    let root = &code_macros[root_name];
     let macro_re = RegexBuilder::new(r"^( *)//\s*<<(.+)>>\n")
         .multi_line(true)
         .build()
         .unwrap();
+    let mut resolved = HashMap::new();
+    let mut total_expansions = 0;
+    resolve_macro(
+        code_macros,
+        root,
+        &macro_re,
+        &mut resolved,
+        &mut total_expansions,
+        0,
+    )
+}
 
-    while let Some(captures) = macro_re.captures(output.as_str()) {
-        let indents: usize = captures.get(1).unwrap().as_str().len() / 4;
-        let macro_name = captures.get(2).unwrap().as_str();
-        let replacement = prepend_indents(
-            code_macros
-                .get(macro_name)
-                .expect("A macro was used, but not defined.")
-                .content
-                .as_str(),
-            indents,
-        );
-        debug!("Expanding macro {macro_name}");
-        output = macro_re.replace(output.as_str(), replacement).into_owned();
+/// Renders a rustc-style labeled snippet for `span` in `source`.
+fn render_snippet(
+    path: &Path,
+    source: &str,
+    span: Span,
+    severity: &str,
+    message: &str,
+) -> String {
+    let line_text = source.lines().nth(span.line - 1).unwrap_or("");
+    let gutter = span.line.to_string();
+    let pad = " ".repeat(gutter.len());
+    let caret = " ".repeat(span.column.saturating_sub(1));
+    format!(
+        "{severity}: {message}\n\
+         {pad}--> {}:{}:{}\n\
+         {pad} |\n\
+         {gutter} | {line_text}\n\
+         {pad} | {caret}^\n",
+        path.display(),
+        span.line,
+        span.column,
+    )
+}
+
+/// A redefinition warning, link error, or tangle success, in the shape
+/// editor/CI problem matchers expect (`--message-format=json`).
+#[derive(Debug, Clone, Serialize)]
+struct Diagnostic {
+    file: String,
+    line: usize,
+    column: usize,
+    severity: &'static str,
+    code: &'static str,
+    message: String,
+}
+
+impl Diagnostic {
+    fn new(
+        path: &Path,
+        span: Span,
+        severity: &'static str,
+        code: &'static str,
+        message: String,
+    ) -> Self {
+        Diagnostic {
+            file: path.display().to_string(),
+            line: span.line,
+            column: span.column,
+            severity,
+            code,
+            message,
+        }
+    }
+}
+
+fn link_error_diagnostics(error: &CodeMacroLinkError) -> Vec<Diagnostic> {
+    match error {
+        CodeMacroLinkError::UndefinedMacro {
+            name,
+            span,
+            origin_file,
+        } => vec![Diagnostic::new(
+            origin_file,
+            *span,
+            "error",
+            "undefined-macro",
+            format!("macro `{name}` is referenced here, but never defined"),
+        )],
+        CodeMacroLinkError::CyclicInclusion(cycle) => cycle
+            .iter()
+            .map(|(name, span, origin_file)| {
+                Diagnostic::new(
+                    origin_file,
+                    *span,
+                    "error",
+                    "cyclic-inclusion",
+                    format!("macro `{name}` is part of this cycle"),
+                )
+            })
+            .collect(),
+        CodeMacroLinkError::ExpansionLimitExceeded {
+            name,
+            span,
+            origin_file,
+        } => vec![Diagnostic::new(
+            origin_file,
+            *span,
+            "error",
+            "expansion-limit-exceeded",
+            format!("expansion of macro `{name}` exceeded the expansion limit; check for runaway recursive references"),
+        )],
     }
-    output
 }
 
-fn tangle(path: &Path) -> Result<(), CodeMacroLinkError> {
-    let input_file_contents = std::fs::read_to_string(path).unwrap();
-    let parser = Parser::new(&input_file_contents);
+/// Where a `Diagnostic` ends up: `pretty_env_logger` output, or JSON lines.
+enum Emitter {
+    Human,
+    Json,
+}
+
+impl Emitter {
+    fn emit(&self, diagnostic: &Diagnostic, source: &str) {
+        match self {
+            Emitter::Human if diagnostic.code == "tangle-success" => {
+                info!("{}", diagnostic.message);
+            }
+            Emitter::Human => {
+                let span = Span {
+                    line: diagnostic.line,
+                    column: diagnostic.column,
+                };
+                let rendered = render_snippet(
+                    Path::new(&diagnostic.file),
+                    source,
+                    span,
+                    diagnostic.severity,
+                    &diagnostic.message,
+                );
+                match diagnostic.severity {
+                    "error" => error!("{rendered}"),
+                    "warning" => warn!("{rendered}"),
+                    _ => info!("{rendered}"),
+                }
+            }
+            Emitter::Json => {
+                println!("{}", serde_json::to_string(diagnostic).unwrap());
+            }
+        }
+    }
+}
+
+/// Collects every `CodeMacro` defined in one markdown file.
+fn parse_file(path: &Path, source: &str) -> Vec<CodeMacro> {
+    let parser = Parser::new(source).into_offset_iter();
     let mut in_rust_code_block = false;
-    let mut code_macros = CodeMacroCollection::new();
-    let mut dependency_graph = DiGraphMap::new();
-    let mut uuid = 0;
+    let mut macros = Vec::new();
 
-    for event in parser {
+    for (event, range) in parser {
         match event {
             Event::Start(CodeBlock(Fenced(Borrowed("rust")))) => {
                 in_rust_code_block = true;
@@ -112,14 +448,8 @@ fn tangle(path: &Path) -> Result<(), CodeMacroLinkError> {
                 if !in_rust_code_block {
                     continue;
                 }
-                if let Ok(mut new_macro) = CodeMacro::try_from(text.into_string()) {
-                    new_macro.uuid = uuid;
-                    uuid += 1;
-                    if code_macros.contains_key(&new_macro.name) {
-                        warn!("Redefinition found for macro {}", new_macro.name);
-                    } else {
-                        code_macros.insert(new_macro.name.clone(), new_macro);
-                    }
+                if let Ok(new_macro) = CodeMacro::parse(text.as_ref(), source, range.start, path) {
+                    macros.push(new_macro);
                 }
             }
             Event::End(CodeBlock(Fenced(Borrowed("rust")))) => {
@@ -129,53 +459,387 @@ fn tangle(path: &Path) -> Result<(), CodeMacroLinkError> {
         }
     }
 
-    let macro_re = RegexBuilder::new(r"^ *//\s*<<(.+)>>\n")
-        .multi_line(true)
-        .build()
-        .unwrap();
+    macros
+}
+
+/// The project-wide link pass: the dependency graph (so callers can ask
+/// "is this root's reachable subgraph affected?"), every problem found,
+/// and the uuids of the macros those problems taint. A root is
+/// only unusable if its own reachable subgraph contains a tainted uuid —
+/// one file's typo must not withhold every other file's output.
+struct LinkResult {
+    dependency_graph: DiGraphMap<usize, ()>,
+    errors: Vec<CodeMacroLinkError>,
+    tainted: HashSet<usize>,
+}
+
+/// Builds the dependency graph and checks for undefined references and
+/// inclusion cycles, across every root rather than bailing at the first
+/// problem found anywhere in the project.
+fn link(code_macros: &CodeMacroCollection) -> LinkResult {
+    let mut dependency_graph = DiGraphMap::new();
+    let mut errors = Vec::new();
+    let mut tainted = HashSet::new();
 
     for macro_definition in code_macros.values() {
-        for captures in macro_re.captures_iter(macro_definition.content.as_str()) {
-            let macro_invokation_name = captures.get(1).unwrap().as_str();
-            let macro_invokation = code_macros.get(macro_invokation_name).unwrap();
-            dependency_graph.add_edge(macro_definition.uuid, macro_invokation.uuid, ());
+        dependency_graph.add_node(macro_definition.uuid);
+        for reference in &macro_definition.references {
+            match code_macros.get(&reference.name) {
+                Some(referenced_macro) => {
+                    dependency_graph.add_edge(macro_definition.uuid, referenced_macro.uuid, ());
+                }
+                None => {
+                    tainted.insert(macro_definition.uuid);
+                    errors.push(CodeMacroLinkError::UndefinedMacro {
+                        name: reference.name.clone(),
+                        span: reference.span,
+                        origin_file: macro_definition.origin_file.clone(),
+                    });
+                }
+            }
         }
     }
 
-    if is_cyclic_directed(&dependency_graph) {
-        return Err(CodeMacroLinkError::CyclicInclusion);
+    let uuid_to_macro: HashMap<usize, &CodeMacro> =
+        code_macros.values().map(|m| (m.uuid, m)).collect();
+    for scc in tarjan_scc(&dependency_graph) {
+        if scc.len() > 1 || dependency_graph.contains_edge(scc[0], scc[0]) {
+            let cycle_members = scc
+                .iter()
+                .map(|uuid| {
+                    let macro_in_cycle = uuid_to_macro[uuid];
+                    (
+                        macro_in_cycle.name.clone(),
+                        macro_in_cycle.span,
+                        macro_in_cycle.origin_file.clone(),
+                    )
+                })
+                .collect();
+            tainted.extend(scc);
+            errors.push(CodeMacroLinkError::CyclicInclusion(cycle_members));
+        }
     }
 
-    let output_path_name = format!(
-        "{}/{}.rs",
-        path.parent().unwrap().to_str().unwrap(),
-        path.file_stem().unwrap().to_str().unwrap()
-    );
+    LinkResult {
+        dependency_graph,
+        errors,
+        tainted,
+    }
+}
 
-    let output_path = Path::new(&output_path_name);
+/// Whether `root_uuid`'s reachable subgraph contains a tainted uuid, i.e.
+/// whether expanding it would eventually hit an undefined reference or a
+/// cycle found by `link`. Roots that don't depend on the broken macro are
+/// left alone.
+fn root_is_tainted(
+    dependency_graph: &DiGraphMap<usize, ()>,
+    tainted: &HashSet<usize>,
+    root_uuid: usize,
+) -> bool {
+    let mut visited = HashSet::new();
+    let mut stack = vec![root_uuid];
+    while let Some(uuid) = stack.pop() {
+        if !visited.insert(uuid) {
+            continue;
+        }
+        if tainted.contains(&uuid) {
+            return true;
+        }
+        stack.extend(dependency_graph.neighbors(uuid));
+    }
+    false
+}
 
-    fs::write(output_path, expand_code_macros(&code_macros).as_str()).unwrap();
+/// `cargo tangle resolve <out.rs>:<line>` — prints the markdown location
+/// that generated a given line of generated output.
+fn resolve_command(spec: &str) {
+    let Some((out_path, line)) = spec.rsplit_once(':') else {
+        error!("Expected <out.rs>:<line>, got `{spec}`");
+        return;
+    };
+    let Ok(line) = line.parse::<usize>() else {
+        error!("`{line}` is not a valid line number");
+        return;
+    };
 
-    info!(
-        "Writing output of {} to {output_path_name}",
-        path.to_str().unwrap()
-    );
+    let map_path = format!("{out_path}.tangle-map");
+    let map_contents = match fs::read_to_string(&map_path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            error!("Failed to read source map {map_path}: {e}");
+            return;
+        }
+    };
+    let entries: Vec<SourceMapEntry> = match serde_json::from_str(&map_contents) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Failed to parse source map {map_path}: {e}");
+            return;
+        }
+    };
 
-    Ok(())
+    match line.checked_sub(1).and_then(|i| entries.get(i)) {
+        Some(entry) => println!("{}:{}", entry.file, entry.line),
+        None => error!("{out_path} has no recorded source for line {line}"),
+    }
 }
 
 fn main() {
     pretty_env_logger::init();
-    let project_dir = env::args().nth(1).unwrap_or(".".to_string());
+    let args: Vec<String> = env::args().collect();
+
+    if args.get(1).map(String::as_str) == Some("resolve") {
+        match args.get(2) {
+            Some(spec) => resolve_command(spec),
+            None => error!("Usage: cargo tangle resolve <out.rs>:<line>"),
+        }
+        return;
+    }
+
+    let emitter = if args.iter().any(|arg| arg == "--message-format=json") {
+        Emitter::Json
+    } else {
+        Emitter::Human
+    };
+    let project_dir = args
+        .iter()
+        .skip(1)
+        .find(|arg| !arg.starts_with("--"))
+        .cloned()
+        .unwrap_or_else(|| ".".to_string());
     let md_glob = format!("{project_dir}/src/**/*.md");
 
+    let mut file_sources: HashMap<PathBuf, String> = HashMap::new();
+    let mut code_macros = CodeMacroCollection::new();
+    let mut next_uuid = 0;
+
     for entry in glob(&md_glob).expect("Failed to read glob pattern") {
-        match entry {
-            Ok(path) => {
-                info!("Tangling {}", path.display());
-                tangle(&path).unwrap();
+        let path = match entry {
+            Ok(path) => path,
+            Err(e) => {
+                error!("{e}");
+                continue;
             }
-            Err(e) => error!("{e}"),
+        };
+        info!("Collecting macros from {}", path.display());
+        let source = std::fs::read_to_string(&path).unwrap();
+        for mut new_macro in parse_file(&path, &source) {
+            new_macro.uuid = next_uuid;
+            next_uuid += 1;
+            if let Some(existing) = code_macros.get(&new_macro.key()) {
+                emitter.emit(
+                    &Diagnostic::new(
+                        &new_macro.origin_file,
+                        new_macro.span,
+                        "warning",
+                        "macro-redefinition",
+                        format!(
+                            "redefinition found for macro {}, first defined in {}",
+                            new_macro.name,
+                            existing.origin_file.display()
+                        ),
+                    ),
+                    &source,
+                );
+            } else {
+                code_macros.insert(new_macro.key(), new_macro);
+            }
+        }
+        file_sources.insert(path, source);
+    }
+
+    let emit_link_error = |error: &CodeMacroLinkError| {
+        for diagnostic in link_error_diagnostics(error) {
+            let source = file_sources
+                .get(Path::new(&diagnostic.file))
+                .map(String::as_str)
+                .unwrap_or("");
+            emitter.emit(&diagnostic, source);
+        }
+    };
+
+    let link_result = link(&code_macros);
+    for error in &link_result.errors {
+        emit_link_error(error);
+    }
+
+    let roots: Vec<(String, PathBuf)> = code_macros
+        .values()
+        .filter_map(|m| m.root_output_path(&project_dir).map(|p| (m.key(), p)))
+        .collect();
+
+    if roots.is_empty() {
+        error!("No root macro found");
+        return;
+    }
+
+    for (root_name, output_path) in roots {
+        let root_uuid = code_macros[&root_name].uuid;
+        if root_is_tainted(&link_result.dependency_graph, &link_result.tainted, root_uuid) {
+            warn!(
+                "Skipping {} because its dependency graph has unresolved link errors",
+                output_path.display()
+            );
+            continue;
+        }
+
+        let expanded = match expand_code_macros(&code_macros, &root_name) {
+            Ok(expanded) => expanded,
+            Err(e) => {
+                emit_link_error(&e);
+                continue;
+            }
+        };
+
+        if let Some(parent) = output_path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::write(&output_path, expanded.text.as_str()).unwrap();
+
+        let map_path = format!("{}.tangle-map", output_path.display());
+        fs::write(
+            &map_path,
+            serde_json::to_string(&expanded.line_origins).unwrap(),
+        )
+        .unwrap();
+
+        let origin_file = &code_macros[&root_name].origin_file;
+        emitter.emit(
+            &Diagnostic::new(
+                origin_file,
+                Span { line: 1, column: 1 },
+                "info",
+                "tangle-success",
+                format!(
+                    "wrote output of {} to {}",
+                    origin_file.display(),
+                    output_path.display()
+                ),
+            ),
+            file_sources
+                .get(origin_file)
+                .map(String::as_str)
+                .unwrap_or(""),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bare_star_roots_are_namespaced_per_file() {
+        let source_a = "// <<*>>=\nfn a() {}\n";
+        let macro_a = CodeMacro::parse(source_a, source_a, 0, Path::new("a.md")).unwrap();
+        let source_b = "// <<*>>=\nfn b() {}\n";
+        let macro_b = CodeMacro::parse(source_b, source_b, 0, Path::new("b.md")).unwrap();
+
+        assert_ne!(macro_a.key(), macro_b.key());
+
+        let mut code_macros = CodeMacroCollection::new();
+        code_macros.insert(macro_a.key(), macro_a);
+        code_macros.insert(macro_b.key(), macro_b);
+
+        // Both files' roots survive the merge instead of one clobbering the other.
+        assert_eq!(code_macros.len(), 2);
+    }
+
+    #[test]
+    fn link_detects_cyclic_inclusion() {
+        let text_a = "// <<a>>=\n// <<b>>\n";
+        let mut macro_a = CodeMacro::parse(text_a, text_a, 0, Path::new("a.md")).unwrap();
+        macro_a.uuid = 0;
+        let text_b = "// <<b>>=\n// <<a>>\n";
+        let mut macro_b = CodeMacro::parse(text_b, text_b, 0, Path::new("b.md")).unwrap();
+        macro_b.uuid = 1;
+
+        let mut code_macros = CodeMacroCollection::new();
+        code_macros.insert(macro_a.key(), macro_a);
+        code_macros.insert(macro_b.key(), macro_b);
+
+        let link_result = link(&code_macros);
+        assert!(matches!(
+            link_result.errors.as_slice(),
+            [CodeMacroLinkError::CyclicInclusion(_)]
+        ));
+        assert!(link_result.tainted.contains(&0));
+        assert!(link_result.tainted.contains(&1));
+    }
+
+    #[test]
+    fn link_does_not_taint_roots_outside_a_broken_cycle() {
+        let text_a = "// <<a>>=\n// <<b>>\n";
+        let mut macro_a = CodeMacro::parse(text_a, text_a, 0, Path::new("a.md")).unwrap();
+        macro_a.uuid = 0;
+        let text_b = "// <<b>>=\n// <<a>>\n";
+        let mut macro_b = CodeMacro::parse(text_b, text_b, 0, Path::new("b.md")).unwrap();
+        macro_b.uuid = 1;
+        let text_root = "// <<*>>=\nfn main() {}\n";
+        let mut unrelated_root =
+            CodeMacro::parse(text_root, text_root, 0, Path::new("c.md")).unwrap();
+        unrelated_root.uuid = 2;
+        let unrelated_root_key = unrelated_root.key();
+
+        let mut code_macros = CodeMacroCollection::new();
+        code_macros.insert(macro_a.key(), macro_a);
+        code_macros.insert(macro_b.key(), macro_b);
+        code_macros.insert(unrelated_root_key.clone(), unrelated_root);
+
+        let link_result = link(&code_macros);
+        let unrelated_root_uuid = code_macros[&unrelated_root_key].uuid;
+        assert!(!root_is_tainted(
+            &link_result.dependency_graph,
+            &link_result.tainted,
+            unrelated_root_uuid
+        ));
+    }
+
+    #[test]
+    fn source_map_round_trips_through_json() {
+        let helper_text = "// <<helper>>=\nfn helper() {}\n";
+        let mut helper_macro =
+            CodeMacro::parse(helper_text, helper_text, 0, Path::new("lib.md")).unwrap();
+        helper_macro.uuid = 0;
+
+        let main_text = "// <<*>>=\nfn main() {\n    // <<helper>>\n}\n";
+        let mut main_macro =
+            CodeMacro::parse(main_text, main_text, 0, Path::new("lib.md")).unwrap();
+        main_macro.uuid = 1;
+        let root_key = main_macro.key();
+
+        let mut code_macros = CodeMacroCollection::new();
+        code_macros.insert(helper_macro.key(), helper_macro);
+        code_macros.insert(root_key.clone(), main_macro);
+
+        let expanded = expand_code_macros(&code_macros, &root_key).unwrap();
+        assert_eq!(
+            expanded.text.split_inclusive('\n').count(),
+            expanded.line_origins.len()
+        );
+
+        // Lines 1-2 are `main`'s own preamble; line 3 is the `<<helper>>`
+        // reference itself, spliced out in favor of `helper`'s two lines
+        // (themselves lines 1-2 of `lib.md`, since `helper` is parsed as its
+        // own standalone source in this test); line 4 is what follows the
+        // reference in `main`, i.e. the closing brace.
+        assert_eq!(
+            expanded.line_origins,
+            vec![
+                SourceMapEntry { file: "lib.md".into(), line: 1 },
+                SourceMapEntry { file: "lib.md".into(), line: 2 },
+                SourceMapEntry { file: "lib.md".into(), line: 1 },
+                SourceMapEntry { file: "lib.md".into(), line: 2 },
+                SourceMapEntry { file: "lib.md".into(), line: 4 },
+            ]
+        );
+
+        let json = serde_json::to_string(&expanded.line_origins).unwrap();
+        let round_tripped: Vec<SourceMapEntry> = serde_json::from_str(&json).unwrap();
+        assert_eq!(round_tripped.len(), expanded.line_origins.len());
+        for (original, round_tripped) in expanded.line_origins.iter().zip(round_tripped.iter()) {
+            assert_eq!(original.file, round_tripped.file);
+            assert_eq!(original.line, round_tripped.line);
         }
     }
 }